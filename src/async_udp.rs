@@ -0,0 +1,157 @@
+//! An async mirror of `udp::Transit`, backed by `tokio::net::UdpSocket`.
+//!
+//! `Transit::recv_from` blocks the calling thread, which makes it awkward to run many endpoints
+//! or integrate with async services. `AsyncTransit` exposes the same `recv_from`/`send_to` shape
+//! as an `async fn` and reuses `Transit`'s header fields (magic, type id, format tag, protocol
+//! version, length, checksum) and `TransitError`, but it is not wire-compatible with `Transit`:
+//! `Transit` always prepends its 20-byte fragment header, even to a single-datagram message, and
+//! `AsyncTransit` does not, so the two cannot talk to each other over the same socket. Use
+//! `AsyncTransit` only between peers that are both `AsyncTransit`. Fragmentation, encryption and
+//! compression are not implemented here yet; a payload must fit in a single datagram.
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use serde::{Serialize, Deserialize};
+use tokio::net::{ToSocketAddrs, UdpSocket};
+
+use udp::{self, Format, TransitError};
+
+const MAX_UDP_SIZE: usize = 65535;
+
+/// The largest datagram handed to the socket: the maximum UDP/IPv4 payload (65535 minus the 8
+/// byte UDP header and 20 byte IPv4 header). Anything larger fails in the socket with `EMSGSIZE`.
+const MAX_SAFE_DATAGRAM_SIZE: usize = 65507;
+
+pub struct AsyncTransit {
+    socket: UdpSocket,
+    buffer: Box<[u8]>,
+    format: Format,
+    type_ids: HashMap<TypeId, u32>,
+}
+
+impl AsyncTransit {
+    pub async fn new<A: ToSocketAddrs>(addr: A) -> Result<AsyncTransit, TransitError> {
+        AsyncTransit::with_format(addr, Format::default_format()).await
+    }
+
+    /// Binds an `AsyncTransit` that serializes and deserializes using `format` instead of
+    /// whichever format is compiled in by default.
+    pub async fn with_format<A: ToSocketAddrs>(addr: A, format: Format) -> Result<AsyncTransit, TransitError> {
+        if !format.is_supported() {
+            return Err(TransitError::UnsupportedFormat);
+        }
+        let socket = UdpSocket::bind(addr).await?;
+        Ok(AsyncTransit {
+            socket: socket,
+            buffer: vec![0u8; MAX_UDP_SIZE].into_boxed_slice(),
+            format: format,
+            type_ids: HashMap::new(),
+        })
+    }
+
+    /// See `udp::Transit::register_type`.
+    pub fn register_type<T: Any>(&mut self, id: u32) {
+        self.type_ids.insert(TypeId::of::<T>(), id);
+    }
+
+    fn type_id_for<T: Any>(&self) -> u32 {
+        *self.type_ids.get(&TypeId::of::<T>()).unwrap_or(&0)
+    }
+
+    /// On success, returns the deserialized value and the sender's address. See
+    /// `udp::Transit::recv_from` for the errors a malformed or mismatched datagram produces.
+    pub async fn recv_from<T>(&mut self) -> Result<(T, SocketAddr), TransitError> where T: Deserialize + Any {
+        let (n, addr) = self.socket.recv_from(&mut self.buffer).await?;
+        if n < udp::HEADER_LEN || self.buffer[0..4] != udp::MAGIC[..] {
+            return Err(TransitError::Framing);
+        }
+        let type_id = udp::read_u32(&self.buffer[4..8]);
+        let format = Format::from_tag(self.buffer[8]).ok_or(TransitError::UnsupportedFormat)?;
+        let version = udp::read_u16(&self.buffer[9..11]);
+        let length = udp::read_u32(&self.buffer[11..15]) as usize;
+        let checksum = udp::read_u32(&self.buffer[15..19]);
+        if n != udp::HEADER_LEN + length {
+            return Err(TransitError::Framing);
+        }
+
+        if version != udp::PROTOCOL_VERSION {
+            return Err(TransitError::VersionMismatch {
+                got: version,
+                expected: udp::PROTOCOL_VERSION..=udp::PROTOCOL_VERSION,
+            });
+        }
+
+        let expected_id = self.type_id_for::<T>();
+        if expected_id != type_id {
+            return Err(TransitError::TypeMismatch);
+        }
+        if !format.is_supported() {
+            return Err(TransitError::UnsupportedFormat);
+        }
+
+        let payload = &self.buffer[udp::HEADER_LEN..udp::HEADER_LEN + length];
+        if udp::crc32(payload) != checksum {
+            return Err(TransitError::Checksum);
+        }
+
+        let data = udp::deserialize(payload, format)?;
+        Ok((data, addr))
+    }
+
+    /// Transforms the packet into a byte array, wraps it in the same frame header `Transit`
+    /// uses, and sends it to the associated address. Returns `TransitError::MessageTooLarge` if
+    /// the framed payload does not fit in a single datagram.
+    pub async fn send_to<T, A>(&mut self, pkt: &T, addr: A) -> Result<(), TransitError> where T: Serialize + Any, A: ToSocketAddrs {
+        let type_id = self.type_id_for::<T>();
+        let format = self.format;
+
+        let mut payload = Vec::new();
+        udp::serialize(&mut payload, pkt, format)?;
+        if udp::HEADER_LEN + payload.len() > MAX_SAFE_DATAGRAM_SIZE {
+            return Err(TransitError::MessageTooLarge);
+        }
+        let checksum = udp::crc32(&payload);
+
+        self.buffer[0..4].copy_from_slice(&udp::MAGIC);
+        udp::write_u32(&mut self.buffer[4..8], type_id);
+        self.buffer[8] = format.tag();
+        udp::write_u16(&mut self.buffer[9..11], udp::PROTOCOL_VERSION);
+        udp::write_u32(&mut self.buffer[11..15], payload.len() as u32);
+        udp::write_u32(&mut self.buffer[15..19], checksum);
+        self.buffer[udp::HEADER_LEN..udp::HEADER_LEN + payload.len()].copy_from_slice(&payload);
+
+        self.socket.send_to(&self.buffer[..udp::HEADER_LEN + payload.len()], addr).await?;
+        Ok(())
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, TransitError> {
+        Ok(self.socket.local_addr()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Clone, PartialEq, PartialOrd, Eq, Ord, Debug)]
+    struct Test {
+        ten: u8,
+    }
+
+    #[tokio::test]
+    async fn test_send_recv() {
+        let mut transit1 = AsyncTransit::new("127.0.0.1:0").await.unwrap();
+        let mut transit2 = AsyncTransit::new("127.0.0.1:0").await.unwrap();
+        let addr1 = transit1.local_addr().unwrap();
+        let test = Test { ten: 10 };
+
+        let res = transit2.send_to(&test, addr1).await;
+        assert!(res.is_ok());
+        let res: Result<(Test, _), TransitError> = transit1.recv_from().await;
+        assert!(res.is_ok());
+        let (data, _addr) = res.unwrap();
+        assert_eq!(data, test);
+    }
+}
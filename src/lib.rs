@@ -10,4 +10,22 @@ extern crate rmp_serde as msgpack;
 #[cfg(feature = "json_serialization")]
 extern crate serde_json;
 
+#[cfg(feature = "encryption")]
+extern crate rand;
+#[cfg(feature = "encryption")]
+extern crate rsa;
+#[cfg(feature = "encryption")]
+extern crate aes;
+#[cfg(feature = "encryption")]
+extern crate cfb8;
+
+#[cfg(feature = "compression")]
+extern crate flate2;
+
+#[cfg(feature = "tokio")]
+extern crate tokio;
+
+#[cfg(feature = "tokio")]
+pub mod async_udp;
+
 include!(concat!(env!("OUT_DIR"), "/lib.rs"));
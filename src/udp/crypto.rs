@@ -0,0 +1,142 @@
+//! Opt-in transport encryption for `Transit`.
+//!
+//! Follows the handshake the stevenarella Minecraft protocol implementation uses: one peer sends
+//! an RSA public key, the other replies with an AES-128 key encrypted under it, and both sides
+//! then use that shared key to drive an AES-128 CFB8 cipher over the datagrams they exchange.
+//! Unlike the Minecraft protocol, which keeps one evolving keystream for the life of a TCP
+//! connection, each UDP datagram is enciphered independently under a fresh random IV carried in
+//! the datagram: UDP routinely drops, reorders and duplicates datagrams, and a single keystream
+//! spanning them would desync permanently after the first such datagram, breaking every one after
+//! it.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+use rsa::{PaddingScheme, PublicKey, RsaPrivateKey, RsaPublicKey};
+use rsa::pkcs1::{FromRsaPublicKey, ToRsaPublicKey};
+use aes::Aes128;
+use cfb8::Cfb8;
+use cfb8::cipher::{AsyncStreamCipher, NewCipher};
+
+use super::TransitError;
+
+const AES_KEY_LEN: usize = 16;
+const RSA_KEY_BITS: usize = 1024;
+
+/// Length of the per-datagram IV prepended to every encrypted datagram on the wire, ahead of the
+/// ciphertext. Sized to the AES block size, which is what `Cfb8::new_from_slices` requires.
+pub(crate) const IV_LEN: usize = 16;
+
+type AesCfb8 = Cfb8<Aes128>;
+
+/// A handshake completed with one particular remote `SocketAddr`: just the shared AES key: a
+/// fresh `AesCfb8` instance keyed from it and a per-datagram IV is built for every
+/// `encrypt`/`decrypt` call, so no mutable cipher state carries over between datagrams.
+struct Session {
+    key: [u8; AES_KEY_LEN],
+}
+
+impl Session {
+    fn from_secret(secret: &[u8; AES_KEY_LEN]) -> Session {
+        Session { key: *secret }
+    }
+
+    fn cipher(&self, iv: &[u8]) -> AesCfb8 {
+        AesCfb8::new_from_slices(&self.key, iv).expect("16 byte key and iv")
+    }
+}
+
+/// Holds this `Transit`'s RSA keypair and the sessions established with remote peers via
+/// `Transit::handshake`/`Transit::accept_handshake`.
+pub struct EncryptionState {
+    private_key: RsaPrivateKey,
+    public_key: RsaPublicKey,
+    sessions: HashMap<SocketAddr, Session>,
+}
+
+impl EncryptionState {
+    pub fn new() -> Result<EncryptionState, TransitError> {
+        let mut rng = OsRng;
+        let private_key = try!(RsaPrivateKey::new(&mut rng, RSA_KEY_BITS).map_err(|_| TransitError::Handshake));
+        let public_key = RsaPublicKey::from(&private_key);
+        Ok(EncryptionState {
+            private_key: private_key,
+            public_key: public_key,
+            sessions: HashMap::new(),
+        })
+    }
+
+    /// DER-encodes our public key so it can be sent as a handshake datagram.
+    pub fn public_key_bytes(&self) -> Result<Vec<u8>, TransitError> {
+        let der = try!(self.public_key.to_pkcs1_der().map_err(|_| TransitError::Handshake));
+        Ok(der.as_der().to_vec())
+    }
+
+    /// Called by the side that received a peer's public key. Generates a fresh shared secret,
+    /// encrypts it under the peer's key, establishes our side of the session, and returns the
+    /// encrypted secret to send back.
+    pub fn begin(&mut self, addr: SocketAddr, peer_public_key_der: &[u8]) -> Result<Vec<u8>, TransitError> {
+        let peer_key = try!(RsaPublicKey::from_pkcs1_der(peer_public_key_der).map_err(|_| TransitError::Handshake));
+
+        let mut secret = [0u8; AES_KEY_LEN];
+        let mut rng = OsRng;
+        rng.fill_bytes(&mut secret);
+
+        let encrypted_secret = try!(peer_key.encrypt(&mut rng, PaddingScheme::new_pkcs1v15_encrypt(), &secret)
+            .map_err(|_| TransitError::Handshake));
+
+        self.sessions.insert(addr, Session::from_secret(&secret));
+        Ok(encrypted_secret)
+    }
+
+    /// Called by the side that sent the original public key, once the peer's encrypted shared
+    /// secret has come back. Decrypts it with our private key and establishes the session.
+    pub fn complete(&mut self, addr: SocketAddr, encrypted_secret: &[u8]) -> Result<(), TransitError> {
+        let secret = try!(self.private_key.decrypt(PaddingScheme::new_pkcs1v15_encrypt(), encrypted_secret)
+            .map_err(|_| TransitError::Handshake));
+        if secret.len() != AES_KEY_LEN {
+            return Err(TransitError::Handshake);
+        }
+        let mut key = [0u8; AES_KEY_LEN];
+        key.copy_from_slice(&secret);
+
+        self.sessions.insert(addr, Session::from_secret(&key));
+        Ok(())
+    }
+
+    /// Encrypts `buf` for `addr` under a fresh random IV and returns `iv ++ ciphertext`, ready to
+    /// send as a datagram. Fails closed with `TransitError::Handshake` if no session has been
+    /// established with `addr` yet — a `Transit` built with `new_encrypted` never sends anything
+    /// in the clear, even to a peer that never completes the handshake.
+    pub fn encrypt(&self, addr: &SocketAddr, buf: &[u8]) -> Result<Vec<u8>, TransitError> {
+        let session = try!(self.sessions.get(addr).ok_or(TransitError::Handshake));
+
+        let mut iv = [0u8; IV_LEN];
+        OsRng.fill_bytes(&mut iv);
+
+        let mut out = Vec::with_capacity(IV_LEN + buf.len());
+        out.extend_from_slice(&iv);
+        out.extend_from_slice(buf);
+        session.cipher(&iv).encrypt(&mut out[IV_LEN..]);
+        Ok(out)
+    }
+
+    /// Undoes `encrypt`: splits the leading IV off `buf` and deciphers the rest under the session
+    /// for `addr`. Fails closed with `TransitError::Handshake` if no session has been established
+    /// with `addr`, and `TransitError::Framing` if a session exists but `buf` is too short to
+    /// hold an IV — in both cases the caller should drop the datagram rather than hand anything
+    /// resembling cleartext up the stack.
+    pub fn decrypt(&self, addr: &SocketAddr, buf: &[u8]) -> Result<Vec<u8>, TransitError> {
+        let session = try!(self.sessions.get(addr).ok_or(TransitError::Handshake));
+
+        if buf.len() < IV_LEN {
+            return Err(TransitError::Framing);
+        }
+        let (iv, ciphertext) = buf.split_at(IV_LEN);
+        let mut plaintext = ciphertext.to_vec();
+        session.cipher(iv).decrypt(&mut plaintext);
+        Ok(plaintext)
+    }
+}
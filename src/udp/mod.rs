@@ -2,6 +2,11 @@ use std::io::{self, Write, Read};
 use std::error::Error;
 use std::net::{UdpSocket, SocketAddr, ToSocketAddrs};
 use std::fmt;
+use std::any::{Any, TypeId};
+use std::cmp;
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
 
 use serde::{Serialize, Deserialize};
 
@@ -15,10 +20,151 @@ use msgpack::encode::Error as SerializeError;
 #[cfg(feature = "json_serialization")]
 use serde_json;
 
+#[cfg(feature = "encryption")]
+mod crypto;
+#[cfg(feature = "encryption")]
+use self::crypto::EncryptionState;
+
+#[cfg(feature = "compression")]
+use flate2::write::ZlibEncoder;
+#[cfg(feature = "compression")]
+use flate2::read::ZlibDecoder;
+#[cfg(feature = "compression")]
+use flate2::Compression;
+
 const MAX_UDP_SIZE: u16 = 65535;
+
+/// The largest datagram we will ever hand to `send_to` on the socket: the maximum UDP/IPv4
+/// payload (65535 minus the 8 byte UDP header and 20 byte IPv4 header). A full `FRAGMENT_HEADER_LEN`
+/// datagram built from `MAX_UDP_SIZE` would exceed this and fail with `EMSGSIZE`, so fragmentation
+/// chunks against this bound instead.
+const MAX_SAFE_DATAGRAM_SIZE: usize = 65507;
+
+/// The wire format used to serialize and deserialize packets.
+///
+/// A single byte identifying the format is carried in every frame header so that peers built with
+/// different sets of serialization features can still tell each other's packets apart (and so
+/// that a binary built with support for more than one format can pick between them at
+/// construction time instead of at compile time).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    MsgPack,
+    Bincode,
+    Postcard,
+}
+
+impl Format {
+    pub(crate) fn tag(&self) -> u8 {
+        match *self {
+            Format::Json => 0,
+            Format::MsgPack => 1,
+            Format::Bincode => 2,
+            Format::Postcard => 3,
+        }
+    }
+
+    pub(crate) fn from_tag(tag: u8) -> Option<Format> {
+        match tag {
+            0 => Some(Format::Json),
+            1 => Some(Format::MsgPack),
+            2 => Some(Format::Bincode),
+            3 => Some(Format::Postcard),
+            _ => None,
+        }
+    }
+
+    /// The format `Transit::new` picks when the caller does not ask for one explicitly, based on
+    /// whichever serialization feature is compiled in.
+    pub(crate) fn default_format() -> Format {
+        #[cfg(feature = "json_serialization")]
+        return Format::Json;
+        #[cfg(all(feature = "msgpack_serialization", not(feature = "json_serialization")))]
+        return Format::MsgPack;
+        #[cfg(not(any(feature = "json_serialization", feature = "msgpack_serialization")))]
+        panic!("Need either json or msgpack feature")
+    }
+
+    pub(crate) fn is_supported(&self) -> bool {
+        match *self {
+            Format::Json => cfg!(feature = "json_serialization"),
+            Format::MsgPack => cfg!(feature = "msgpack_serialization"),
+            Format::Bincode => false,
+            Format::Postcard => false,
+        }
+    }
+}
+
+/// Magic bytes that open every frame, used by `recv_from` to reject datagrams that did not come
+/// from a `Transit`.
+pub(crate) const MAGIC: [u8; 4] = *b"TRNS";
+
+/// `magic(4) + type id(4) + format(1) + protocol version(2) + payload length(4) + checksum(4)`.
+pub(crate) const HEADER_LEN: usize = 19;
+
+/// The protocol version this build of the crate embeds in every outgoing frame. Bump this
+/// alongside incompatible changes to the frame layout so that `set_accepted_protocol_versions`
+/// can be used to reject datagrams from old or new peers instead of misparsing them.
+pub(crate) const PROTOCOL_VERSION: u16 = 1;
+
+/// `message id(8) + fragment index(4) + fragment count(4) + fragment length(4)`, prefixed on
+/// every datagram so a frame larger than one datagram can be split across several and
+/// reassembled on the other end.
+const FRAGMENT_HEADER_LEN: usize = 20;
+
+/// Default cap on the total size of a reassembled message, guarding against a peer exhausting
+/// memory by announcing a huge fragment count and never completing it. Override with
+/// `set_max_message_size`.
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Default amount of time an incomplete message is kept around waiting for its remaining
+/// fragments before being dropped. Override with `set_fragment_timeout`.
+const DEFAULT_FRAGMENT_TIMEOUT_SECS: u64 = 30;
+
+/// Fragments of a message that have arrived so far, keyed by the `(SocketAddr, message id)` pair
+/// they were reassembled under.
+struct PartialMessage {
+    fragments: HashMap<u32, Vec<u8>>,
+    total_fragments: u32,
+    total_len: usize,
+    received_at: Instant,
+}
+
+impl PartialMessage {
+    fn new(total_fragments: u32) -> PartialMessage {
+        PartialMessage {
+            fragments: HashMap::new(),
+            total_fragments: total_fragments,
+            total_len: 0,
+            received_at: Instant::now(),
+        }
+    }
+}
+
 pub struct Transit {
     socket: UdpSocket,
     buffer: Box<[u8]>,
+    format: Format,
+    /// Maps a sent/received Rust type to the `u32` id peers agree to use for it on the wire, as
+    /// registered through `register_type`. A type that was never registered is treated as id `0`,
+    /// so it is only distinguished from other unregistered types if they were given distinct ids.
+    type_ids: HashMap<TypeId, u32>,
+    /// Messages in the middle of being reassembled from fragments.
+    reassembly: HashMap<(SocketAddr, u64), PartialMessage>,
+    fragment_timeout: Duration,
+    max_message_size: usize,
+    /// Id of the next outgoing message, incremented per `send_to` call.
+    next_message_id: u64,
+    /// Present when this `Transit` was built with `new_encrypted`; holds the RSA keypair and the
+    /// per-peer AES-128 CFB8 sessions established via `handshake`/`accept_handshake`.
+    #[cfg(feature = "encryption")]
+    encryption: Option<EncryptionState>,
+    /// Set via `set_compression_threshold`. When `Some(n)`, payloads larger than `n` bytes are
+    /// deflated before being framed.
+    compression_threshold: Option<usize>,
+    /// Protocol versions `recv_from` will accept from a peer, set via
+    /// `set_accepted_protocol_versions`. Defaults to just `PROTOCOL_VERSION`.
+    accepted_versions: RangeInclusive<u16>,
 }
 
 pub type UnderlyingError = Box<Error + Send + Sync>;
@@ -28,6 +174,31 @@ pub enum TransitError {
     SerializeError(UnderlyingError),
     DeserializeError(UnderlyingError),
     Error(UnderlyingError),
+    /// The peer sent (or the caller requested) a `Format` that this `Transit` was not built with
+    /// support for.
+    UnsupportedFormat,
+    /// The datagram's header was missing the magic bytes or did not match its declared length.
+    Framing,
+    /// The datagram's registered type id did not match the type `recv_from` was asked to
+    /// deserialize into.
+    TypeMismatch,
+    /// The datagram's payload did not match the checksum carried in its header.
+    Checksum,
+    /// A reassembled message (or a message the caller tried to send) exceeded `max_message_size`,
+    /// or a frame passed to `AsyncTransit::send_to` did not fit in a single datagram.
+    MessageTooLarge,
+    /// The encryption handshake with a peer failed: a malformed key, a bad reply, or a reply
+    /// from an unexpected address.
+    Handshake,
+    /// A payload needed to be compressed or decompressed but this `Transit` was not built with
+    /// the `compression` feature.
+    UnsupportedCompression,
+    /// The datagram's protocol version fell outside the range this `Transit` will accept; see
+    /// `set_accepted_protocol_versions`.
+    VersionMismatch {
+        got: u16,
+        expected: RangeInclusive<u16>,
+    },
 }
 
 impl Error for TransitError {
@@ -37,6 +208,14 @@ impl Error for TransitError {
             TransitError::SerializeError(ref err) => err.description(),
             TransitError::DeserializeError(ref err) => err.description(),
             TransitError::Error(ref err) => err.description(),
+            TransitError::UnsupportedFormat => "packet used a serialization format this Transit does not support",
+            TransitError::Framing => "packet was missing the Transit frame header or its length did not match",
+            TransitError::TypeMismatch => "packet's registered type id did not match the expected type",
+            TransitError::Checksum => "packet payload did not match its header checksum",
+            TransitError::MessageTooLarge => "message exceeded the configured maximum message size",
+            TransitError::Handshake => "encryption handshake with peer failed",
+            TransitError::UnsupportedCompression => "payload was compressed, or needed compressing, but the compression feature is not compiled in",
+            TransitError::VersionMismatch { .. } => "packet's protocol version is not accepted by this Transit",
         }
     }
 
@@ -46,6 +225,14 @@ impl Error for TransitError {
             TransitError::SerializeError(ref err) => err.cause(),
             TransitError::DeserializeError(ref err) => err.cause(),
             TransitError::Error(ref err) => err.cause(),
+            TransitError::UnsupportedFormat => None,
+            TransitError::Framing => None,
+            TransitError::TypeMismatch => None,
+            TransitError::Checksum => None,
+            TransitError::MessageTooLarge => None,
+            TransitError::Handshake => None,
+            TransitError::UnsupportedCompression => None,
+            TransitError::VersionMismatch { .. } => None,
         }
     }
 }
@@ -88,6 +275,22 @@ impl fmt::Display for TransitError {
                 write!(fmt, "SerializeError: {}", err),
             TransitError::Error(ref err) =>
                 write!(fmt, "Error: {}", err),
+            TransitError::UnsupportedFormat =>
+                write!(fmt, "UnsupportedFormat: packet format is not supported by this Transit"),
+            TransitError::Framing =>
+                write!(fmt, "Framing: packet is missing the Transit frame header"),
+            TransitError::TypeMismatch =>
+                write!(fmt, "TypeMismatch: packet's type id did not match the expected type"),
+            TransitError::Checksum =>
+                write!(fmt, "Checksum: packet payload failed its checksum"),
+            TransitError::MessageTooLarge =>
+                write!(fmt, "MessageTooLarge: message exceeded the configured maximum message size"),
+            TransitError::Handshake =>
+                write!(fmt, "Handshake: encryption handshake with peer failed"),
+            TransitError::UnsupportedCompression =>
+                write!(fmt, "UnsupportedCompression: compression feature is not compiled in"),
+            TransitError::VersionMismatch { got, ref expected } =>
+                write!(fmt, "VersionMismatch: packet's protocol version {} is not in the accepted range {:?}", got, expected),
         }
     }
 }
@@ -95,8 +298,22 @@ impl fmt::Display for TransitError {
 /// Sends and receives types over UDP, removing any knowledge of buffers and dealing with the std
 /// library.
 ///
-/// This use the `bincode` crate to serialize objects. Does not currently support securely sending
-/// packets over the network or ensuring that only packets of the correct type are serialized.
+/// Every message is wrapped in a small frame: magic bytes, a format tag identifying the
+/// `Format` the payload was serialized with, the type id registered for the payload type (see
+/// `register_type`), a length, and a checksum over the payload. `recv_from` verifies all of this
+/// before handing the payload to the deserializer, so a receiver can tell a malformed or
+/// wrong-type datagram from a real one instead of silently mis-decoding it. Frames larger than a
+/// single UDP datagram are transparently split into fragments on send and reassembled on
+/// receive, so `send_to`/`recv_from` are not limited by `MAX_UDP_SIZE`; see
+/// `set_fragment_timeout` and `set_max_message_size` for the knobs that bound how much state an
+/// incomplete message can hold. Use `Transit::with_format` to pick a format explicitly, or
+/// `Transit::new` to pick whichever format is compiled in. With the `encryption` feature,
+/// `Transit::new_encrypted` plus a `handshake`/`accept_handshake` pair adds confidentiality on
+/// top of this, encrypting every datagram exchanged with a peer under an AES-128 CFB8 session.
+/// `set_compression_threshold` deflates payloads past a configurable size before they are framed.
+/// Every frame also carries the sender's `PROTOCOL_VERSION`; `set_accepted_protocol_versions`
+/// controls which versions `recv_from` will accept from a peer instead of misparsing a frame
+/// layout it doesn't understand.
 ///
 /// # Examples
 ///
@@ -117,31 +334,329 @@ impl fmt::Display for TransitError {
 /// ```
 impl Transit {
     pub fn new<A>(addr: A) -> Result<Transit, TransitError> where A: ToSocketAddrs {
+        Transit::with_format(addr, Format::default_format())
+    }
+
+    /// Binds a `Transit` that serializes and deserializes using `format` instead of whichever
+    /// format is compiled in by default. Returns `TransitError::UnsupportedFormat` if `format`
+    /// was not compiled in.
+    pub fn with_format<A>(addr: A, format: Format) -> Result<Transit, TransitError> where A: ToSocketAddrs {
+        if !format.is_supported() {
+            return Err(TransitError::UnsupportedFormat);
+        }
         let socket = try!(UdpSocket::bind(addr));
         Ok(Transit {
             socket: socket,
             buffer: udp_buffer(),
+            format: format,
+            type_ids: HashMap::new(),
+            reassembly: HashMap::new(),
+            fragment_timeout: Duration::from_secs(DEFAULT_FRAGMENT_TIMEOUT_SECS),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            next_message_id: 0,
+            #[cfg(feature = "encryption")]
+            encryption: None,
+            compression_threshold: None,
+            accepted_versions: PROTOCOL_VERSION..=PROTOCOL_VERSION,
         })
     }
 
+    /// Binds a `Transit` ready to exchange encrypted datagrams. Call `handshake` (or
+    /// `accept_handshake` on the other side) with a peer's address before sending or receiving
+    /// anything from it: this fails closed rather than falling back to the clear. `send_to`
+    /// returns `TransitError::Handshake` for a `SocketAddr` without an established session;
+    /// `recv_from` silently drops a datagram from one, the same as any other malformed datagram,
+    /// and keeps waiting.
+    #[cfg(feature = "encryption")]
+    pub fn new_encrypted<A>(addr: A) -> Result<Transit, TransitError> where A: ToSocketAddrs {
+        let mut transit = try!(Transit::new(addr));
+        transit.encryption = Some(try!(EncryptionState::new()));
+        Ok(transit)
+    }
+
+    /// Sends our RSA public key to `addr` and blocks waiting for its encrypted reply, deriving
+    /// the AES-128 CFB8 session that subsequent `send_to`/`recv_from` calls to and from `addr`
+    /// will be encrypted under. The peer must be waiting in `accept_handshake`. Only valid on a
+    /// `Transit` built with `new_encrypted`.
+    #[cfg(feature = "encryption")]
+    pub fn handshake<A>(&mut self, addr: A) -> Result<(), TransitError> where A: ToSocketAddrs {
+        let addr = try!(try!(addr.to_socket_addrs()).next().ok_or(TransitError::Handshake));
+        let public_key = {
+            let state = try!(self.encryption.as_ref().ok_or(TransitError::Handshake));
+            try!(state.public_key_bytes())
+        };
+        try!(self.socket.send_to(&public_key, addr));
+
+        let mut reply = vec![0u8; MAX_UDP_SIZE as usize];
+        let (n, from) = try!(self.socket.recv_from(&mut reply));
+        if from != addr {
+            return Err(TransitError::Handshake);
+        }
+        let state = try!(self.encryption.as_mut().ok_or(TransitError::Handshake));
+        state.complete(addr, &reply[..n])
+    }
+
+    /// Blocks waiting for a peer to start a handshake, replies with our shared secret encrypted
+    /// under the peer's public key, and establishes the session for the peer's address. Returns
+    /// the peer's address on success. Only valid on a `Transit` built with `new_encrypted`.
+    #[cfg(feature = "encryption")]
+    pub fn accept_handshake(&mut self) -> Result<SocketAddr, TransitError> {
+        let mut request = vec![0u8; MAX_UDP_SIZE as usize];
+        let (n, addr) = try!(self.socket.recv_from(&mut request));
+        let reply = {
+            let state = try!(self.encryption.as_mut().ok_or(TransitError::Handshake));
+            try!(state.begin(addr, &request[..n]))
+        };
+        try!(self.socket.send_to(&reply, addr));
+        Ok(addr)
+    }
+
+    /// Registers the wire type id that `T` will be framed with on send, and checked against on
+    /// receive. Peers must register the same id for the same type for `recv_from::<T>()` to
+    /// accept each other's packets. A type that is never registered is framed and expected with
+    /// id `0`, so `recv_from` gives no protection between two unregistered types sent to each
+    /// other: register every type you want `TypeMismatch` to actually catch.
+    ///
+    /// Looks `T` up by `TypeId`, which requires `T: 'static`; `send_to`/`recv_from` carry the
+    /// same bound for the same reason. A borrowed type whose lifetime is not `'static` (e.g. a
+    /// `&'a [u8]` or `&'a str` tied to a shorter-lived buffer) cannot be registered or sent —
+    /// send an owned value (`Vec<u8>`, `String`) instead.
+    pub fn register_type<T: Any>(&mut self, id: u32) {
+        self.type_ids.insert(TypeId::of::<T>(), id);
+    }
+
+    fn type_id_for<T: Any>(&self) -> u32 {
+        *self.type_ids.get(&TypeId::of::<T>()).unwrap_or(&0)
+    }
+
+    /// Sets how long an incompletely reassembled message is kept around before its fragments are
+    /// dropped. Guards against a fleet of fragments that will never complete (a dropped
+    /// fragment, a dead peer) pinning memory forever.
+    pub fn set_fragment_timeout(&mut self, timeout: Duration) {
+        self.fragment_timeout = timeout;
+    }
+
+    /// Sets the largest reassembled message (header included) this `Transit` will accept or
+    /// send, guarding against a peer that announces a huge fragment count to exhaust memory.
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.max_message_size = size;
+    }
+
+    /// When `Some(n)`, payloads larger than `n` bytes are deflated before being sent, shrinking
+    /// bandwidth for larger or text-heavy payloads at the cost of a little CPU. `None` (the
+    /// default) never compresses.
+    pub fn set_compression_threshold(&mut self, threshold: Option<usize>) {
+        self.compression_threshold = threshold;
+    }
+
+    /// The protocol version this `Transit` embeds in every outgoing frame.
+    pub fn protocol_version(&self) -> u16 {
+        PROTOCOL_VERSION
+    }
+
+    /// Sets the range of protocol versions `recv_from` will accept from a peer; datagrams
+    /// carrying a version outside it are rejected with `TransitError::VersionMismatch` instead of
+    /// being parsed. Defaults to `PROTOCOL_VERSION..=PROTOCOL_VERSION`, i.e. only peers running
+    /// this exact version. Widen this when rolling out a frame layout change to accept both the
+    /// old and new version for the duration of the rollout.
+    pub fn set_accepted_protocol_versions(&mut self, versions: RangeInclusive<u16>) {
+        self.accepted_versions = versions;
+    }
+
+    fn evict_expired_messages(&mut self) {
+        let timeout = self.fragment_timeout;
+        let now = Instant::now();
+        let expired: Vec<(SocketAddr, u64)> = self.reassembly.iter()
+            .filter(|&(_, msg)| now.duration_since(msg.received_at) >= timeout)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in expired {
+            self.reassembly.remove(&key);
+        }
+    }
+
     /// On success, this function returns the type deserialized using the Deserialize trait
-    /// implementation. It is not defined what happens when Transit trys to deserialize a different
-    /// type into another currently.
-    pub fn recv_from<T>(&mut self) -> Result<(T, SocketAddr), TransitError> where T: Deserialize {
-        let (n, addr) = try!(self.socket.recv_from(&mut self.buffer));
-        let data = try!(deserialize(&self.buffer[..n]));
+    /// implementation. Blocks, reading and buffering fragments, until a full message addressed to
+    /// this `Transit` has arrived. Returns `TransitError::Framing` if a reassembled message is not
+    /// a well-formed `Transit` frame, `TransitError::TypeMismatch` if its registered type id does
+    /// not match `T`'s, `TransitError::Checksum` if the payload does not match its checksum,
+    /// `TransitError::MessageTooLarge` if it exceeds `max_message_size`, and
+    /// `TransitError::UnsupportedFormat` if it was serialized with a format this `Transit` was not
+    /// built with support for.
+    ///
+    /// `T: Any` requires `T: 'static`; see `register_type` for what that rules out.
+    pub fn recv_from<T>(&mut self) -> Result<(T, SocketAddr), TransitError> where T: Deserialize + Any {
+        loop {
+            let (n, addr) = try!(self.socket.recv_from(&mut self.buffer));
+            #[cfg(feature = "encryption")]
+            let n = {
+                match self.encryption {
+                    Some(ref state) => {
+                        let plaintext = match state.decrypt(&addr, &self.buffer[..n]) {
+                            Ok(plaintext) => plaintext,
+                            Err(_) => continue,
+                        };
+                        self.buffer[..plaintext.len()].copy_from_slice(&plaintext);
+                        plaintext.len()
+                    }
+                    None => n,
+                }
+            };
+            if n < FRAGMENT_HEADER_LEN {
+                continue;
+            }
+            let message_id = read_u64(&self.buffer[0..8]);
+            let frag_index = read_u32(&self.buffer[8..12]);
+            let frag_count = read_u32(&self.buffer[12..16]);
+            let frag_len = read_u32(&self.buffer[16..20]) as usize;
+            if frag_count == 0 || frag_index >= frag_count || n != FRAGMENT_HEADER_LEN + frag_len {
+                continue;
+            }
+            let chunk = self.buffer[FRAGMENT_HEADER_LEN..FRAGMENT_HEADER_LEN + frag_len].to_vec();
+
+            self.evict_expired_messages();
+
+            let key = (addr, message_id);
+            let max_size = self.max_message_size;
+            let mut too_large = false;
+            let mut complete_frame: Option<Vec<u8>> = None;
+            {
+                let entry = self.reassembly.entry(key).or_insert_with(|| PartialMessage::new(frag_count));
+                if entry.total_fragments == frag_count && !entry.fragments.contains_key(&frag_index) {
+                    entry.total_len += frag_len;
+                    entry.fragments.insert(frag_index, chunk);
+                    if entry.total_len > max_size {
+                        too_large = true;
+                    } else if entry.fragments.len() as u32 == entry.total_fragments {
+                        let mut frame = Vec::with_capacity(entry.total_len);
+                        for idx in 0..entry.total_fragments {
+                            frame.extend_from_slice(&entry.fragments[&idx]);
+                        }
+                        complete_frame = Some(frame);
+                    }
+                }
+            }
+            if too_large {
+                self.reassembly.remove(&key);
+                return Err(TransitError::MessageTooLarge);
+            }
+            if let Some(frame) = complete_frame {
+                self.reassembly.remove(&key);
+                return self.decode_frame(frame, addr);
+            }
+        }
+    }
+
+    fn decode_frame<T>(&self, frame: Vec<u8>, addr: SocketAddr) -> Result<(T, SocketAddr), TransitError> where T: Deserialize + Any {
+        if frame.len() < HEADER_LEN || frame[0..4] != MAGIC[..] {
+            return Err(TransitError::Framing);
+        }
+        let type_id = read_u32(&frame[4..8]);
+        let format = try!(Format::from_tag(frame[8]).ok_or(TransitError::UnsupportedFormat));
+        let version = read_u16(&frame[9..11]);
+        let length = read_u32(&frame[11..15]) as usize;
+        let checksum = read_u32(&frame[15..19]);
+        if frame.len() != HEADER_LEN + length {
+            return Err(TransitError::Framing);
+        }
+
+        if !self.accepted_versions.contains(&version) {
+            return Err(TransitError::VersionMismatch {
+                got: version,
+                expected: self.accepted_versions.clone(),
+            });
+        }
+
+        let expected_id = self.type_id_for::<T>();
+        if expected_id != type_id {
+            return Err(TransitError::TypeMismatch);
+        }
+        if !format.is_supported() {
+            return Err(TransitError::UnsupportedFormat);
+        }
+
+        let payload = &frame[HEADER_LEN..HEADER_LEN + length];
+        if crc32(payload) != checksum {
+            return Err(TransitError::Checksum);
+        }
+
+        let payload = try!(decompress_payload(payload, self.max_message_size));
+        let data = try!(deserialize(&payload[..], format));
         Ok((data, addr))
     }
 
-    /// Transforms the packet into a byte array and sends it to the associated address.
-    pub fn send_to<T, A>(&mut self, pkt: &T, addr: A) -> Result<(), TransitError> where T: Serialize, A: ToSocketAddrs {
-        let n = {
-            let bytes = &mut self.buffer[..];
-            let mut buf = ByteCounter::new(bytes);
-            try!(serialize(&mut buf, pkt));
-            buf.write_count()
-        };
-        try!(self.socket.send_to(&self.buffer[..n], addr));
+    /// Transforms the packet into a byte array, wraps it in a frame header carrying the
+    /// registered type id, format tag, length and checksum, and sends it to the associated
+    /// address, splitting the frame across multiple datagrams if it does not fit in one.
+    /// Returns `TransitError::MessageTooLarge` if the frame exceeds `max_message_size`.
+    ///
+    /// `T: Any` requires `T: 'static`; see `register_type` for what that rules out.
+    pub fn send_to<T, A>(&mut self, pkt: &T, addr: A) -> Result<(), TransitError> where T: Serialize + Any, A: ToSocketAddrs {
+        let addr = try!(try!(addr.to_socket_addrs()).next().ok_or(TransitError::IoError(
+            io::Error::new(io::ErrorKind::InvalidInput, "no addresses to send to")
+        )));
+        let type_id = self.type_id_for::<T>();
+        let format = self.format;
+
+        let mut serialized = Vec::new();
+        try!(serialize(&mut serialized, pkt, format));
+        let payload = try!(compress_payload(&serialized, self.compression_threshold));
+        let checksum = crc32(&payload);
+
+        let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+        frame.extend_from_slice(&MAGIC);
+        let mut field = [0u8; 4];
+        write_u32(&mut field, type_id);
+        frame.extend_from_slice(&field);
+        frame.push(format.tag());
+        let mut version_field = [0u8; 2];
+        write_u16(&mut version_field, PROTOCOL_VERSION);
+        frame.extend_from_slice(&version_field);
+        write_u32(&mut field, payload.len() as u32);
+        frame.extend_from_slice(&field);
+        write_u32(&mut field, checksum);
+        frame.extend_from_slice(&field);
+        frame.extend_from_slice(&payload);
+
+        if frame.len() > self.max_message_size {
+            return Err(TransitError::MessageTooLarge);
+        }
+
+        // A session adds a per-datagram IV ahead of the ciphertext, so datagrams carrying one
+        // need that much headroom kept clear of `MAX_SAFE_DATAGRAM_SIZE` as well.
+        #[cfg(feature = "encryption")]
+        let max_chunk = MAX_SAFE_DATAGRAM_SIZE - FRAGMENT_HEADER_LEN
+            - if self.encryption.is_some() { crypto::IV_LEN } else { 0 };
+        #[cfg(not(feature = "encryption"))]
+        let max_chunk = MAX_SAFE_DATAGRAM_SIZE - FRAGMENT_HEADER_LEN;
+        let total_fragments = ((frame.len() + max_chunk - 1) / max_chunk).max(1) as u32;
+        let message_id = self.next_message_id;
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+
+        for idx in 0..total_fragments {
+            let start = idx as usize * max_chunk;
+            let end = cmp::min(start + max_chunk, frame.len());
+            let chunk_len = end - start;
+
+            write_u64(&mut self.buffer[0..8], message_id);
+            write_u32(&mut self.buffer[8..12], idx);
+            write_u32(&mut self.buffer[12..16], total_fragments);
+            write_u32(&mut self.buffer[16..20], chunk_len as u32);
+            self.buffer[FRAGMENT_HEADER_LEN..FRAGMENT_HEADER_LEN + chunk_len].copy_from_slice(&frame[start..end]);
+            let dgram_len = FRAGMENT_HEADER_LEN + chunk_len;
+
+            #[cfg(feature = "encryption")]
+            {
+                if let Some(ref state) = self.encryption {
+                    let encrypted = try!(state.encrypt(&addr, &self.buffer[..dgram_len]));
+                    try!(self.socket.send_to(&encrypted, addr));
+                } else {
+                    try!(self.socket.send_to(&self.buffer[..dgram_len], addr));
+                }
+            }
+            #[cfg(not(feature = "encryption"))]
+            try!(self.socket.send_to(&self.buffer[..dgram_len], addr));
+        }
         Ok(())
     }
 
@@ -158,68 +673,160 @@ fn udp_buffer() -> Box<[u8]> {
         .into_boxed_slice()
 }
 
-#[cfg(feature = "msgpack_serialization")]
-fn serialize<W, T>(mut buf: W, val: &T) -> Result<(), TransitError> where W: Write, T: Serialize {
-    try!(val.serialize(&mut Serializer::new(&mut buf)));
-    Ok(())
+pub(crate) fn serialize<W, T>(mut buf: W, val: &T, format: Format) -> Result<(), TransitError> where W: Write, T: Serialize {
+    match format {
+        #[cfg(feature = "msgpack_serialization")]
+        Format::MsgPack => {
+            try!(val.serialize(&mut Serializer::new(&mut buf)));
+            Ok(())
+        }
+        #[cfg(feature = "json_serialization")]
+        Format::Json => {
+            try!(serde_json::to_writer(&mut buf, &val));
+            Ok(())
+        }
+        _ => Err(TransitError::UnsupportedFormat),
+    }
 }
 
-#[cfg(feature = "json_serialization")]
-fn serialize<W, T>(mut buf: W, val: &T) -> Result<(), TransitError> where W: Write, T: Serialize {
-    try!(serde_json::to_writer(&mut buf, &val));
-    Ok(())
+pub(crate) fn deserialize<R, T>(buf: R, format: Format) -> Result<T, TransitError> where R: Read, T: Deserialize {
+    match format {
+        #[cfg(feature = "msgpack_serialization")]
+        Format::MsgPack => {
+            let data = try!(Deserialize::deserialize(&mut Deserializer::new(buf)));
+            Ok(data)
+        }
+        #[cfg(feature = "json_serialization")]
+        Format::Json => {
+            let data = try!(serde_json::de::from_reader(buf));
+            Ok(data)
+        }
+        _ => Err(TransitError::UnsupportedFormat),
+    }
 }
 
-#[cfg(not(any(feature = "json_serialization", feature = "msgpack_serialization")))]
-fn serialize<W, T>(mut _buf: W, _val: &T) -> Result<(), TransitError> where W: Write, T: Serialize {
-    panic!("Need either json or msgpack feature")
+pub(crate) fn write_u32(buf: &mut [u8], val: u32) {
+    buf[0] = (val >> 24) as u8;
+    buf[1] = (val >> 16) as u8;
+    buf[2] = (val >> 8) as u8;
+    buf[3] = val as u8;
 }
 
-#[cfg(feature = "msgpack_serialization")]
-fn deserialize<R, T>(buf: R) -> Result<T, TransitError> where R: Read, T: Deserialize {
-    let data = try!(Deserialize::deserialize(&mut Deserializer::new(buf)));
-    Ok(data)
+pub(crate) fn read_u32(buf: &[u8]) -> u32 {
+    ((buf[0] as u32) << 24) | ((buf[1] as u32) << 16) | ((buf[2] as u32) << 8) | (buf[3] as u32)
 }
 
-#[cfg(feature = "json_serialization")]
-fn deserialize<R, T>(buf: R) -> Result<T, TransitError> where R: Read, T: Deserialize {
-    let data = try!(serde_json::de::from_reader(buf));
-    Ok(data)
+pub(crate) fn write_u16(buf: &mut [u8], val: u16) {
+    buf[0] = (val >> 8) as u8;
+    buf[1] = val as u8;
+}
+
+pub(crate) fn read_u16(buf: &[u8]) -> u16 {
+    ((buf[0] as u16) << 8) | (buf[1] as u16)
 }
 
-#[cfg(not(any(feature = "json_serialization", feature = "msgpack_serialization")))]
-fn deserialize<R, T>(_buf: R) -> Result<T, TransitError> where R: Read, T: Deserialize {
-    panic!("Need either json or msgpack feature")
+fn write_u64(buf: &mut [u8], val: u64) {
+    for i in 0..8 {
+        buf[i] = (val >> (8 * (7 - i))) as u8;
+    }
 }
 
-struct ByteCounter<W> {
-    counter: usize,
-    writer: W,
+fn read_u64(buf: &[u8]) -> u64 {
+    let mut val: u64 = 0;
+    for i in 0..8 {
+        val = (val << 8) | (buf[i] as u64);
+    }
+    val
 }
 
-impl<W> ByteCounter<W> {
-    fn new(writer: W) -> ByteCounter<W> {
-        ByteCounter {
-            counter: 0,
-            writer: writer,
+/// CRC-32 (IEEE 802.3 polynomial), computed bit by bit rather than via a lookup table since this
+/// crate has no use for the extra speed.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFFFFFF;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB88320 & mask);
         }
     }
+    !crc
+}
 
-    fn write_count(&self) -> usize {
-        self.counter
+/// Wraps `data` with a one byte compression flag: `0` followed by `data` unchanged if `data` is
+/// at or under `threshold` (or `threshold` is `None`), otherwise `1`, the uncompressed length,
+/// and the deflated bytes.
+fn compress_payload(data: &[u8], threshold: Option<usize>) -> Result<Vec<u8>, TransitError> {
+    if let Some(n) = threshold {
+        if data.len() > n {
+            return compress_zlib(data);
+        }
     }
+    let mut out = Vec::with_capacity(1 + data.len());
+    out.push(0u8);
+    out.extend_from_slice(data);
+    Ok(out)
 }
 
-impl<W: Write> Write for ByteCounter<W> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let n = try!(self.writer.write(buf));
-        self.counter += n;
-        Ok(n)
+/// Undoes `compress_payload`. Bounds the inflated output to `max_size` (the caller's
+/// `max_message_size`) rather than trusting the declared uncompressed length carried in the
+/// datagram, which a malicious peer controls and could set near `u32::MAX` to force an unbounded
+/// allocation (a decompression bomb) from a tiny compressed datagram.
+fn decompress_payload(data: &[u8], max_size: usize) -> Result<Vec<u8>, TransitError> {
+    if data.is_empty() {
+        return Err(TransitError::Framing);
+    }
+    match data[0] {
+        0 => Ok(data[1..].to_vec()),
+        1 => decompress_zlib(&data[1..], max_size),
+        _ => Err(TransitError::Framing),
     }
+}
+
+#[cfg(feature = "compression")]
+fn compress_zlib(data: &[u8]) -> Result<Vec<u8>, TransitError> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    try!(encoder.write_all(data));
+    let compressed = try!(encoder.finish());
+
+    let mut out = Vec::with_capacity(5 + compressed.len());
+    out.push(1u8);
+    let mut len_buf = [0u8; 4];
+    write_u32(&mut len_buf, data.len() as u32);
+    out.extend_from_slice(&len_buf);
+    out.extend_from_slice(&compressed);
+    Ok(out)
+}
+
+#[cfg(not(feature = "compression"))]
+fn compress_zlib(_data: &[u8]) -> Result<Vec<u8>, TransitError> {
+    Err(TransitError::UnsupportedCompression)
+}
 
-    fn flush(&mut self) -> io::Result<()> {
-        self.writer.flush()
+#[cfg(feature = "compression")]
+fn decompress_zlib(data: &[u8], max_size: usize) -> Result<Vec<u8>, TransitError> {
+    if data.len() < 4 {
+        return Err(TransitError::Framing);
     }
+    let uncompressed_len = read_u32(&data[0..4]) as usize;
+    if uncompressed_len > max_size {
+        return Err(TransitError::MessageTooLarge);
+    }
+    let mut decoder = ZlibDecoder::new(&data[4..]);
+    let mut out = Vec::with_capacity(cmp::min(uncompressed_len, max_size));
+    // Cap the read at `max_size`, not the peer-declared `uncompressed_len`, so a forged length
+    // can't make this allocate toward an attacker-chosen size; a forged length is instead caught
+    // below by the actual inflated size not matching what was declared.
+    try!((&mut decoder).take(max_size as u64).read_to_end(&mut out));
+    if out.len() != uncompressed_len {
+        return Err(TransitError::Framing);
+    }
+    Ok(out)
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress_zlib(_data: &[u8], _max_size: usize) -> Result<Vec<u8>, TransitError> {
+    Err(TransitError::UnsupportedCompression)
 }
 
 #[cfg(test)]
@@ -271,13 +878,15 @@ mod test {
     fn test_send_recv_bytes() {
         let mut transit1 = Transit::new("127.0.0.1:0").unwrap();
         let mut transit2 = Transit::new("127.0.0.1:0").unwrap();
+        // Sends the owned `Vec<u8>` rather than a borrowed `&[u8]` slice: `send_to`/`recv_from`
+        // require `T: Any`, which in turn requires `T: 'static`, so a non-'static borrowed type
+        // can no longer be sent directly (see `Transit::register_type`).
         let vec = vec!(9u8);
-        let slice = &vec[..];
         let addr1 = transit1.local_addr().unwrap();
 
-        let res = transit2.send_to(&slice, addr1);
+        let res = transit2.send_to(&vec, addr1);
         assert!(res.is_ok());
-        let res = transit2.send_to(&slice, addr1);
+        let res = transit2.send_to(&vec, addr1);
         assert!(res.is_ok());
 
         let res: Result<(Vec<u8>, _), TransitError> = transit1.recv_from();
@@ -290,6 +899,12 @@ mod test {
         assert_eq!(data, vec);
     }
 
+    #[test]
+    fn test_with_format_unsupported() {
+        let res = Transit::with_format("127.0.0.1:0", Format::Bincode);
+        assert!(res.is_err());
+    }
+
     #[test]
     fn test_packet_type() {
         let addr1 = "127.0.0.1:0";
@@ -304,6 +919,107 @@ mod test {
         assert!(res.is_err());
     }
 
+    #[test]
+    fn test_registered_type_mismatch() {
+        let mut transit1 = Transit::new("127.0.0.1:0").unwrap();
+        let mut transit2 = Transit::new("127.0.0.1:0").unwrap();
+        transit1.register_type::<Another>(1);
+        transit2.register_type::<Test>(2);
+        let addr2 = transit2.local_addr().unwrap();
+
+        let res = transit1.send_to(&Another { data: String::from("Hello") }, addr2);
+        assert!(res.is_ok());
+        let res: Result<(Test, _), TransitError> = transit2.recv_from();
+        match res {
+            Err(TransitError::TypeMismatch) => (),
+            _ => panic!("expected TypeMismatch"),
+        }
+    }
+
+    #[test]
+    fn test_version_mismatch_is_rejected() {
+        let mut transit1 = Transit::new("127.0.0.1:0").unwrap();
+        let mut transit2 = Transit::new("127.0.0.1:0").unwrap();
+        transit2.set_accepted_protocol_versions(2..=2);
+        let addr2 = transit2.local_addr().unwrap();
+
+        let res = transit1.send_to(&Test { ten: 10 }, addr2);
+        assert!(res.is_ok());
+        let res: Result<(Test, _), TransitError> = transit2.recv_from();
+        match res {
+            Err(TransitError::VersionMismatch { got: 1, expected }) => assert_eq!(expected, 2..=2),
+            _ => panic!("expected VersionMismatch"),
+        }
+    }
+
+    #[test]
+    fn test_fragmented_message() {
+        let mut transit1 = Transit::new("127.0.0.1:0").unwrap();
+        let mut transit2 = Transit::new("127.0.0.1:0").unwrap();
+        let addr2 = transit2.local_addr().unwrap();
+        let big: Vec<u8> = (0..200_000).map(|x| (x % 251) as u8).collect();
+
+        let res = transit1.send_to(&big, addr2);
+        assert!(res.is_ok());
+        let res: Result<(Vec<u8>, _), TransitError> = transit2.recv_from();
+        assert!(res.is_ok());
+        let (data, _addr) = res.unwrap();
+        assert_eq!(data, big);
+    }
+
+    #[test]
+    fn test_message_too_large_is_rejected() {
+        let mut transit1 = Transit::new("127.0.0.1:0").unwrap();
+        transit1.set_max_message_size(10);
+        let big: Vec<u8> = vec![0u8; 1000];
+
+        let res = transit1.send_to(&big, "127.0.0.1:1");
+        match res {
+            Err(TransitError::MessageTooLarge) => (),
+            _ => panic!("expected MessageTooLarge"),
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encrypted_round_trip() {
+        let mut transit1 = Transit::new_encrypted("127.0.0.1:0").unwrap();
+        let mut transit2 = Transit::new_encrypted("127.0.0.1:0").unwrap();
+        let addr2 = transit2.local_addr().unwrap();
+
+        let accepted = ::std::thread::spawn(move || {
+            let from = transit2.accept_handshake().unwrap();
+            (transit2, from)
+        });
+        transit1.handshake(addr2).unwrap();
+        let (mut transit2, _from) = accepted.join().unwrap();
+
+        let test = Test { ten: 10 };
+        let res = transit1.send_to(&test, transit2.local_addr().unwrap());
+        assert!(res.is_ok());
+        let res: Result<(Test, _), TransitError> = transit2.recv_from();
+        assert!(res.is_ok());
+        let (data, _addr) = res.unwrap();
+        assert_eq!(data, test);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_compressed_round_trip() {
+        let mut transit1 = Transit::new("127.0.0.1:0").unwrap();
+        let mut transit2 = Transit::new("127.0.0.1:0").unwrap();
+        transit1.set_compression_threshold(Some(16));
+        let addr2 = transit2.local_addr().unwrap();
+        let data = Another { data: "x".repeat(1000) };
+
+        let res = transit1.send_to(&data, addr2);
+        assert!(res.is_ok());
+        let res: Result<(Another, _), TransitError> = transit2.recv_from();
+        assert!(res.is_ok());
+        let (received, _addr) = res.unwrap();
+        assert_eq!(received, data);
+    }
+
     // FIXME: rmp-serde does not current support enums, see issue #42
     #[cfg(feature = "json_serialization")]
     #[test]